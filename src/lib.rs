@@ -1,130 +1,1170 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
 
-/// A two dimensional table of integers.
+mod parser;
+mod persistence;
+pub use parser::ParseError;
+pub use persistence::{replay, EventLog};
+
+/// A cell value: either a number (kept as `Int` until an operation forces a
+/// `Float` promotion), a `Bool` (produced by comparisons), or `Null` (an
+/// empty cell). Arithmetic between `Int`s stays `Int`; mixing in a `Float`
+/// promotes the result to `Float`; any operator applied to a `Null` operand
+/// itself produces `Null`. An `Int` result that would overflow also produces
+/// `Null` rather than panicking, the same as division/modulo by zero.
+/// `Sum`/aggregates are the exception: they treat `Null` as their identity
+/// element rather than propagating it, so a `Null` cell simply doesn't
+/// contribute to the total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f64),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    fn numeric(&self) -> Option<f64> {
+        match self {
+            Value::Int(v) => Some(*v as f64),
+            Value::Float(v) => Some(*v),
+            Value::Bool(_) | Value::Null => None,
+        }
+    }
+
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Bool(v) => *v,
+            Value::Int(v) => *v != 0,
+            Value::Float(v) => *v != 0.0,
+            Value::Null => false,
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Value::Int(v) => *v == 0,
+            Value::Float(v) => *v == 0.0,
+            Value::Bool(_) | Value::Null => false,
+        }
+    }
+
+    /// `Int op Int` stays `Int`; a `Float` on either side promotes the
+    /// result to `Float`; `Null` (or a non-numeric `Bool`) on either side
+    /// produces `Null`. An `Int` result that would overflow also produces
+    /// `Null`, rather than panicking, the same "missing value" signal as
+    /// any other undefined result.
+    fn numeric_op(self, other: Value, int_op: fn(i32, i32) -> Option<i32>, float_op: fn(f64, f64) -> f64) -> Value {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => int_op(a, b).map_or(Value::Null, Value::Int),
+            (a, b) => match (a.numeric(), b.numeric()) {
+                (Some(a), Some(b)) => Value::Float(float_op(a, b)),
+                _ => Value::Null,
+            },
+        }
+    }
+
+    /// An `Int` result that would overflow produces `Null`, same as
+    /// `numeric_op`.
+    fn pow(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) if b >= 0 => {
+                a.checked_pow(b as u32).map_or(Value::Null, Value::Int)
+            }
+            (a, b) => match (a.numeric(), b.numeric()) {
+                (Some(a), Some(b)) => Value::Float(a.powf(b)),
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+impl std::ops::Add for Value {
+    type Output = Value;
+    fn add(self, other: Value) -> Value {
+        self.numeric_op(other, i32::checked_add, |a, b| a + b)
+    }
+}
+
+impl std::ops::Sub for Value {
+    type Output = Value;
+    fn sub(self, other: Value) -> Value {
+        self.numeric_op(other, i32::checked_sub, |a, b| a - b)
+    }
+}
+
+impl std::ops::Mul for Value {
+    type Output = Value;
+    fn mul(self, other: Value) -> Value {
+        self.numeric_op(other, i32::checked_mul, |a, b| a * b)
+    }
+}
+
+impl std::ops::Div for Value {
+    type Output = Value;
+    /// A zero divisor, or an `Int` result that would overflow (`i32::MIN /
+    /// -1`), produces `Null` rather than panicking — the same "missing
+    /// value" signal as any other undefined result.
+    fn div(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.checked_div(b).map_or(Value::Null, Value::Int),
+            (a, b) => match (a.numeric(), b.numeric()) {
+                (Some(_), Some(b)) if b == 0.0 => Value::Null,
+                (Some(a), Some(b)) => Value::Float(a / b),
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+impl std::ops::Rem for Value {
+    type Output = Value;
+    /// A zero divisor, or an `Int` result that would overflow (`i32::MIN %
+    /// -1`), produces `Null` rather than panicking, same as `Div`.
+    fn rem(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.checked_rem(b).map_or(Value::Null, Value::Int),
+            (a, b) => match (a.numeric(), b.numeric()) {
+                (Some(_), Some(b)) if b == 0.0 => Value::Null,
+                (Some(a), Some(b)) => Value::Float(a % b),
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+impl std::ops::Neg for Value {
+    type Output = Value;
+    fn neg(self) -> Value {
+        match self {
+            Value::Int(v) => v.checked_neg().map_or(Value::Null, Value::Int),
+            Value::Float(v) => Value::Float(-v),
+            Value::Bool(_) | Value::Null => Value::Null,
+        }
+    }
+}
+
+/// Treat a reduction input as its identity element (`0`) when it is `Null`,
+/// rather than letting `Null` contaminate the whole reduction — the rule
+/// `Sum`/aggregates use, in contrast to every other operator's propagation.
+fn null_as_zero(value: Value) -> Value {
+    if value.is_null() {
+        Value::Int(0)
+    } else {
+        value
+    }
+}
+
+/// A `Value` ordered by an explicit total order (`Null < Bool < number`,
+/// numbers compared numerically), so it can be used as a `BTreeMap` key for
+/// the `Min`/`Max` multisets below.
+#[derive(Debug, Clone, Copy)]
+struct ValueKey(Value);
+
+impl ValueKey {
+    fn rank(&self) -> u8 {
+        match self.0 {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Int(_) | Value::Float(_) => 2,
+        }
+    }
+}
+
+impl PartialEq for ValueKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ValueKey {}
+
+impl PartialOrd for ValueKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ValueKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.rank().cmp(&other.rank()) {
+            Ordering::Equal => match (self.0, other.0) {
+                (Value::Null, Value::Null) => Ordering::Equal,
+                (Value::Bool(a), Value::Bool(b)) => a.cmp(&b),
+                (a, b) => a.numeric().unwrap_or(0.0).total_cmp(&b.numeric().unwrap_or(0.0)),
+            },
+            ord => ord,
+        }
+    }
+}
+
+/// A two dimensional table of cell values.
 pub struct Table {
     pub name: String,
-    pub data: Vec<Vec<i32>>,
+    pub data: Vec<Vec<Value>>,
 }
 
 pub type TableSet = HashMap<String, Table>;
 
 impl Table {
-    pub fn new(name: String, data: Vec<Vec<i32>>) -> Self {
+    pub fn new(name: String, data: Vec<Vec<Value>>) -> Self {
         Self { name, data }
     }
 
-    pub fn get(&self, x: usize, y: usize) -> Option<&i32> {
+    pub fn get(&self, x: usize, y: usize) -> Option<&Value> {
         self.data.get(y).and_then(|row| row.get(x))
     }
 
-    pub fn set(&mut self, x: usize, y: usize, value: i32) {
+    pub fn set(&mut self, x: usize, y: usize, value: Value) {
         if let Some(row) = self.data.get_mut(y) {
             if let Some(cell) = row.get_mut(x) {
                 *cell = value;
             }
         }
     }
+
+    /// Values of the cells inside `[x0, x1) x [y0, y1)` that actually exist
+    /// in this (possibly ragged) table.
+    pub fn region(&self, x0: usize, x1: usize, y0: usize, y1: usize) -> impl Iterator<Item = &Value> {
+        let y_end = y1.min(self.data.len());
+        self.data[y0.min(y_end)..y_end].iter().flat_map(move |row| {
+            let x_end = x1.min(row.len());
+            row[x0.min(x_end)..x_end].iter()
+        })
+    }
+}
+
+/// Kind of reduction an [`Expression::Aggregate`] applies over its region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateKind {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+/// A binary operator whose result is recomputed from its operands on every
+/// change rather than maintained incrementally, since it is cheap and
+/// non-associative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Mod,
+    Pow,
+}
+
+impl BinOp {
+    /// Any `Null` operand makes the result `Null`, same as every operator
+    /// except `Sum`/aggregates.
+    fn apply(self, lhs: Value, rhs: Value) -> Value {
+        if lhs.is_null() || rhs.is_null() {
+            return Value::Null;
+        }
+        match self {
+            BinOp::Eq => Value::Bool(lhs == rhs),
+            BinOp::Ne => Value::Bool(lhs != rhs),
+            BinOp::Lt => Value::Bool(ValueKey(lhs) < ValueKey(rhs)),
+            BinOp::Le => Value::Bool(ValueKey(lhs) <= ValueKey(rhs)),
+            BinOp::Gt => Value::Bool(ValueKey(lhs) > ValueKey(rhs)),
+            BinOp::Ge => Value::Bool(ValueKey(lhs) >= ValueKey(rhs)),
+            BinOp::And => Value::Bool(lhs.truthy() && rhs.truthy()),
+            BinOp::Or => Value::Bool(lhs.truthy() || rhs.truthy()),
+            BinOp::Mod => lhs.rem(rhs),
+            BinOp::Pow => lhs.pow(rhs),
+        }
+    }
 }
 
 /// Computed Expression
+#[derive(Debug)]
 pub enum Expression {
-    Number(i32),
+    Number(Value),
     Reference { table: String, x: usize, y: usize },
     Sum(Vec<Expression>),
+    Sub(Box<Expression>, Box<Expression>),
+    Mul(Vec<Expression>),
+    Div(Box<Expression>, Box<Expression>),
+    Neg(Box<Expression>),
+    Min(Vec<Expression>),
+    Max(Vec<Expression>),
+    /// Reduction over the rectangular region `[x0, x1) x [y0, y1)` of `table`.
+    Aggregate {
+        table: String,
+        x0: usize,
+        x1: usize,
+        y0: usize,
+        y1: usize,
+        kind: AggregateKind,
+    },
+    Binary(BinOp, Box<Expression>, Box<Expression>),
 }
 
 impl Expression {
-    pub fn eval(&self, table_set: &TableSet) -> i32 {
+    pub fn eval(&self, table_set: &TableSet) -> Value {
         match self {
             Expression::Number(v) => *v,
             Expression::Reference { table, x, y } => table_set
                 .get(table)
                 .and_then(|table| table.get(*x, *y).copied())
                 .unwrap(),
-            Expression::Sum(args) => args.iter().fold(0, |acc, v| v.eval(table_set) + acc),
+            // `Null` is treated as the identity element (`0`), not propagated.
+            Expression::Sum(args) => args
+                .iter()
+                .fold(Value::Int(0), |acc, v| acc.add(null_as_zero(v.eval(table_set)))),
+            Expression::Sub(lhs, rhs) => lhs.eval(table_set).sub(rhs.eval(table_set)),
+            Expression::Mul(args) => {
+                let values: Vec<Value> = args.iter().map(|v| v.eval(table_set)).collect();
+                if values.iter().any(Value::is_null) {
+                    Value::Null
+                } else {
+                    values.into_iter().fold(Value::Int(1), Value::mul)
+                }
+            }
+            Expression::Div(lhs, rhs) => lhs.eval(table_set).div(rhs.eval(table_set)),
+            Expression::Neg(arg) => arg.eval(table_set).neg(),
+            // `MIN()`/`MAX()` with no arguments has no extreme to report;
+            // fall back to `0`, matching `Aggregate`'s Min/Max over an empty
+            // region a few lines below.
+            Expression::Min(args) => {
+                let values: Vec<Value> = args.iter().map(|v| v.eval(table_set)).collect();
+                if values.iter().any(Value::is_null) {
+                    Value::Null
+                } else {
+                    values
+                        .into_iter()
+                        .min_by(|a, b| ValueKey(*a).cmp(&ValueKey(*b)))
+                        .unwrap_or(Value::Int(0))
+                }
+            }
+            Expression::Max(args) => {
+                let values: Vec<Value> = args.iter().map(|v| v.eval(table_set)).collect();
+                if values.iter().any(Value::is_null) {
+                    Value::Null
+                } else {
+                    values
+                        .into_iter()
+                        .max_by(|a, b| ValueKey(*a).cmp(&ValueKey(*b)))
+                        .unwrap_or(Value::Int(0))
+                }
+            }
+            Expression::Aggregate {
+                table,
+                x0,
+                x1,
+                y0,
+                y1,
+                kind,
+            } => {
+                let values: Vec<Value> = table_set
+                    .get(table)
+                    .map(|table| table.region(*x0, *x1, *y0, *y1).copied().collect())
+                    .unwrap_or_default();
+                // `Null` cells are treated as absent rather than propagated,
+                // except by `Count`, which counts every cell in the region.
+                let non_null: Vec<Value> = values.iter().copied().filter(|v| !v.is_null()).collect();
+                match kind {
+                    AggregateKind::Count => Value::Int(values.len() as i32),
+                    AggregateKind::Sum => non_null.into_iter().fold(Value::Int(0), Value::add),
+                    AggregateKind::Min => non_null
+                        .into_iter()
+                        .min_by(|a, b| ValueKey(*a).cmp(&ValueKey(*b)))
+                        .unwrap_or(Value::Int(0)),
+                    AggregateKind::Max => non_null
+                        .into_iter()
+                        .max_by(|a, b| ValueKey(*a).cmp(&ValueKey(*b)))
+                        .unwrap_or(Value::Int(0)),
+                    AggregateKind::Avg => {
+                        if non_null.is_empty() {
+                            Value::Int(0)
+                        } else {
+                            let count = non_null.len() as i32;
+                            non_null.into_iter().fold(Value::Int(0), Value::add).div(Value::Int(count))
+                        }
+                    }
+                }
+            }
+            Expression::Binary(op, lhs, rhs) => op.apply(lhs.eval(table_set), rhs.eval(table_set)),
         }
     }
+
+    /// Parse a textual formula, e.g. `SUM(t1!0,0, 1) * (3 - t2!0,0)`.
+    pub fn parse(input: &str) -> Result<Expression, ParseError> {
+        parser::parse(input)
+    }
 }
 
-pub enum PersistentExpression {
-    Number(i32),
-    Reference {
-        /// Value state
-        state: i32,
-        /// Identifier
-        table: String,
-        x: usize,
-        y: usize,
+/// Index of a node inside a [`PersistentExpression`]'s arena.
+pub type NodeId = usize;
+
+/// Shape of a node, mirroring [`Expression`] but referencing children by
+/// [`NodeId`] instead of owning them, so the tree can be walked by index.
+///
+/// `Mul`/`Min`/`Max` carry extra bookkeeping alongside their children so
+/// `apply` can update the node's state from a single changed child without
+/// rescanning the rest: `Mul` keeps a running product of the nonzero
+/// children plus a count of zero children (the product is `0` while any
+/// zero is present, and is recovered once the count drops back to zero);
+/// `Min`/`Max` keep a multiset of child states so the extreme can be read
+/// off the map's first/last key in O(log n). Both also track a `null_count`,
+/// since (unlike `Sum`/aggregates) these reductions propagate `Null` rather
+/// than treating it as an identity: the state is `Null` while any child is.
+/// `Sub`/`Div`/`Neg` are cheap and non-associative, so they are simply
+/// recomputed from their children on every change instead of being
+/// maintained incrementally.
+#[derive(Debug, Clone)]
+enum NodeKind {
+    Number(Value),
+    Reference { table: String, x: usize, y: usize },
+    Sum(Vec<NodeId>),
+    Sub(NodeId, NodeId),
+    Mul {
+        children: Vec<NodeId>,
+        nonzero_product: Value,
+        zero_count: usize,
+        null_count: usize,
+    },
+    Div(NodeId, NodeId),
+    Neg(NodeId),
+    Min {
+        children: Vec<NodeId>,
+        multiset: BTreeMap<ValueKey, usize>,
+        null_count: usize,
     },
-    Sum {
-        /// Accumulation state
-        state: i32,
-        args: Vec<PersistentExpression>,
+    Max {
+        children: Vec<NodeId>,
+        multiset: BTreeMap<ValueKey, usize>,
+        null_count: usize,
+    },
+    /// Reduction over `[x0, x1) x [y0, y1)` of `table`. `cells` caches the
+    /// last known value of every cell the region has seen, so an update can
+    /// be applied to `accumulator` as an old-to-new delta instead of
+    /// rescanning the region.
+    Aggregate {
+        table: String,
+        x0: usize,
+        x1: usize,
+        y0: usize,
+        y1: usize,
+        accumulator: AggregateAccumulator,
+        cells: HashMap<(usize, usize), Value>,
     },
+    /// Like `Div`/`Neg`: cheap and non-associative, so recomputed on every
+    /// change instead of being maintained incrementally.
+    Binary(BinOp, NodeId, NodeId),
 }
 
-impl PersistentExpression {
-    pub fn state(&self) -> i32 {
-        match self {
-            PersistentExpression::Number(v) => *v,
-            PersistentExpression::Reference { state, .. } => *state,
-            PersistentExpression::Sum { state, .. } => *state,
+/// Incrementally maintained reduction backing an `Aggregate` node. `Null`
+/// cells are excluded from `Sum`/`Avg`/`Min`/`Max` (treated as identity),
+/// while `Count` counts every cell in the region, `Null` or not, matching
+/// [`Expression::eval`]'s `Aggregate` arm.
+#[derive(Debug, Clone)]
+enum AggregateAccumulator {
+    Count(usize),
+    Sum(Value),
+    Avg { sum: Value, count: usize },
+    Min(BTreeMap<ValueKey, usize>),
+    Max(BTreeMap<ValueKey, usize>),
+}
+
+impl AggregateAccumulator {
+    fn empty(kind: AggregateKind) -> Self {
+        match kind {
+            AggregateKind::Count => AggregateAccumulator::Count(0),
+            AggregateKind::Sum => AggregateAccumulator::Sum(Value::Int(0)),
+            AggregateKind::Avg => AggregateAccumulator::Avg {
+                sum: Value::Int(0),
+                count: 0,
+            },
+            AggregateKind::Min => AggregateAccumulator::Min(BTreeMap::new()),
+            AggregateKind::Max => AggregateAccumulator::Max(BTreeMap::new()),
         }
     }
 
-    /// Apply event, return true if current state is modified.
-    pub fn apply(&mut self, event: &TableEvent) -> bool {
+    fn state(&self) -> Value {
         match self {
-            PersistentExpression::Number(_) => false,
-            PersistentExpression::Reference { state, table, x, y } => {
-                let TableEvent::SetValue {
-                    table: t,
-                    x: x1,
-                    y: y1,
-                    value,
-                } = event;
-                if t == table && x == x1 && y == y1 {
-                    *state = *value;
-                    true
+            AggregateAccumulator::Count(count) => Value::Int(*count as i32),
+            AggregateAccumulator::Sum(sum) => *sum,
+            AggregateAccumulator::Avg { sum, count } => {
+                if *count == 0 {
+                    Value::Int(0)
                 } else {
-                    false
+                    sum.div(Value::Int(*count as i32))
                 }
             }
-            PersistentExpression::Sum { state, args } => {
-                let mut modified = false;
-                for arg in args.iter_mut() {
-                    let original_state = arg.state();
-                    if arg.apply(event) {
-                        *state += arg.state() - original_state;
-                        modified = true;
-                    } else {
-                        continue;
+            AggregateAccumulator::Min(multiset) => {
+                multiset.keys().next().map(|k| k.0).unwrap_or(Value::Int(0))
+            }
+            AggregateAccumulator::Max(multiset) => {
+                multiset.keys().next_back().map(|k| k.0).unwrap_or(Value::Int(0))
+            }
+        }
+    }
+
+    /// Seed the accumulator with a cell present in the region at `init`
+    /// time. A `Null` cell doesn't contribute (`Count` still counts it).
+    fn insert(&mut self, value: Value) {
+        match self {
+            AggregateAccumulator::Count(count) => *count += 1,
+            AggregateAccumulator::Sum(sum) => {
+                if !value.is_null() {
+                    *sum = sum.add(value);
+                }
+            }
+            AggregateAccumulator::Avg { sum, count } => {
+                if !value.is_null() {
+                    *sum = sum.add(value);
+                    *count += 1;
+                }
+            }
+            AggregateAccumulator::Min(multiset) | AggregateAccumulator::Max(multiset) => {
+                if !value.is_null() {
+                    *multiset.entry(ValueKey(value)).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    /// Move an already-seeded cell from `old` to `new`.
+    fn update(&mut self, old: Value, new: Value) {
+        match self {
+            AggregateAccumulator::Count(_) => {}
+            AggregateAccumulator::Sum(sum) => {
+                *sum = sum.add(null_as_zero(new)).sub(null_as_zero(old));
+            }
+            AggregateAccumulator::Avg { sum, count } => match (old.is_null(), new.is_null()) {
+                (true, true) => {}
+                (true, false) => {
+                    *sum = sum.add(new);
+                    *count += 1;
+                }
+                (false, true) => {
+                    *sum = sum.sub(old);
+                    *count -= 1;
+                }
+                (false, false) => {
+                    *sum = sum.add(new).sub(old);
+                }
+            },
+            AggregateAccumulator::Min(multiset) | AggregateAccumulator::Max(multiset) => {
+                if !old.is_null() {
+                    if let Some(count) = multiset.get_mut(&ValueKey(old)) {
+                        *count -= 1;
+                        if *count == 0 {
+                            multiset.remove(&ValueKey(old));
+                        }
                     }
                 }
+                if !new.is_null() {
+                    *multiset.entry(ValueKey(new)).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Move a child's contribution to a `Min`/`Max` multiset from `old` to
+/// `new`, tracking how many children are currently `Null` via `null_count`
+/// (the reduction's state is `Null` while that count is nonzero).
+fn move_in_value_multiset(
+    multiset: &mut BTreeMap<ValueKey, usize>,
+    null_count: &mut usize,
+    old: Value,
+    new: Value,
+) {
+    if old.is_null() {
+        *null_count -= 1;
+    } else if let Some(count) = multiset.get_mut(&ValueKey(old)) {
+        *count -= 1;
+        if *count == 0 {
+            multiset.remove(&ValueKey(old));
+        }
+    }
+    if new.is_null() {
+        *null_count += 1;
+    } else {
+        *multiset.entry(ValueKey(new)).or_insert(0) += 1;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    kind: NodeKind,
+    parent: Option<NodeId>,
+    state: Value,
+}
+
+type Dispatch = HashMap<(String, usize, usize), Vec<NodeId>>;
+type RegionDispatch = HashMap<String, Vec<NodeId>>;
+
+/// A flattened, incrementally maintained [`Expression`].
+///
+/// Nodes live in a single arena (`nodes`) addressed by [`NodeId`], each
+/// carrying a `parent` pointer, so `apply` only has to touch the nodes on
+/// the path from an updated leaf to the root instead of walking the whole
+/// tree. `dispatch` maps each referenced table cell to the `Reference`
+/// leaves that depend on it, and `region_dispatch` maps each referenced
+/// table to the `Aggregate` nodes that might cover a given event's cell, so
+/// a `TableEvent` can jump straight to the affected nodes.
+pub struct PersistentExpression {
+    nodes: Vec<Node>,
+    root: NodeId,
+    dispatch: Dispatch,
+    region_dispatch: RegionDispatch,
+}
+
+impl PersistentExpression {
+    /// Flatten an [`Expression`] into an arena-backed persistent expression.
+    pub fn build(expr: &Expression) -> Self {
+        let mut nodes = Vec::new();
+        let root = Self::push(&mut nodes, None, expr);
+        let (dispatch, region_dispatch) = Self::build_dispatch(&nodes);
+
+        Self {
+            nodes,
+            root,
+            dispatch,
+            region_dispatch,
+        }
+    }
 
-                modified
+    /// Derive `dispatch`/`region_dispatch` from `nodes`. Both maps are purely
+    /// a function of the arena, so this is reused by [`Self::build`] and by
+    /// snapshot loading instead of persisting them directly.
+    fn build_dispatch(nodes: &[Node]) -> (Dispatch, RegionDispatch) {
+        let mut dispatch: Dispatch = HashMap::new();
+        let mut region_dispatch: RegionDispatch = HashMap::new();
+        for (id, node) in nodes.iter().enumerate() {
+            match &node.kind {
+                NodeKind::Reference { table, x, y } => {
+                    dispatch.entry((table.clone(), *x, *y)).or_default().push(id);
+                }
+                NodeKind::Aggregate { table, .. } => {
+                    region_dispatch.entry(table.clone()).or_default().push(id);
+                }
+                _ => {}
             }
         }
+        (dispatch, region_dispatch)
     }
 
-    /// Initialize state of persistent expression
-    pub fn init(&mut self, table_set: &TableSet) {
-        match self {
-            PersistentExpression::Number(_) => {}
-            PersistentExpression::Reference { state, table, x, y } => {
-                *state = table_set
-                    .get(table)
-                    .and_then(|table| table.get(*x, *y).copied())
-                    .unwrap();
+    fn push(nodes: &mut Vec<Node>, parent: Option<NodeId>, expr: &Expression) -> NodeId {
+        match expr {
+            Expression::Number(v) => {
+                nodes.push(Node {
+                    kind: NodeKind::Number(*v),
+                    parent,
+                    state: *v,
+                });
+                nodes.len() - 1
+            }
+            Expression::Reference { table, x, y } => {
+                nodes.push(Node {
+                    kind: NodeKind::Reference {
+                        table: table.clone(),
+                        x: *x,
+                        y: *y,
+                    },
+                    parent,
+                    state: Value::Null,
+                });
+                nodes.len() - 1
+            }
+            Expression::Sum(args) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Sum(Vec::new()),
+                    parent,
+                    state: Value::Int(0),
+                });
+                let children: Vec<NodeId> = args
+                    .iter()
+                    .map(|arg| Self::push(nodes, Some(id), arg))
+                    .collect();
+                nodes[id].kind = NodeKind::Sum(children);
+                id
+            }
+            Expression::Sub(lhs, rhs) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Sub(0, 0),
+                    parent,
+                    state: Value::Int(0),
+                });
+                let lhs = Self::push(nodes, Some(id), lhs);
+                let rhs = Self::push(nodes, Some(id), rhs);
+                nodes[id].kind = NodeKind::Sub(lhs, rhs);
+                id
+            }
+            Expression::Mul(args) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Mul {
+                        children: Vec::new(),
+                        nonzero_product: Value::Int(1),
+                        zero_count: 0,
+                        null_count: 0,
+                    },
+                    parent,
+                    state: Value::Int(0),
+                });
+                let children: Vec<NodeId> = args
+                    .iter()
+                    .map(|arg| Self::push(nodes, Some(id), arg))
+                    .collect();
+                nodes[id].kind = NodeKind::Mul {
+                    children,
+                    nonzero_product: Value::Int(1),
+                    zero_count: 0,
+                    null_count: 0,
+                };
+                id
+            }
+            Expression::Div(lhs, rhs) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Div(0, 0),
+                    parent,
+                    state: Value::Int(0),
+                });
+                let lhs = Self::push(nodes, Some(id), lhs);
+                let rhs = Self::push(nodes, Some(id), rhs);
+                nodes[id].kind = NodeKind::Div(lhs, rhs);
+                id
+            }
+            Expression::Neg(arg) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Neg(0),
+                    parent,
+                    state: Value::Int(0),
+                });
+                let child = Self::push(nodes, Some(id), arg);
+                nodes[id].kind = NodeKind::Neg(child);
+                id
+            }
+            Expression::Min(args) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Min {
+                        children: Vec::new(),
+                        multiset: BTreeMap::new(),
+                        null_count: 0,
+                    },
+                    parent,
+                    state: Value::Int(0),
+                });
+                let children: Vec<NodeId> = args
+                    .iter()
+                    .map(|arg| Self::push(nodes, Some(id), arg))
+                    .collect();
+                nodes[id].kind = NodeKind::Min {
+                    children,
+                    multiset: BTreeMap::new(),
+                    null_count: 0,
+                };
+                id
+            }
+            Expression::Max(args) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Max {
+                        children: Vec::new(),
+                        multiset: BTreeMap::new(),
+                        null_count: 0,
+                    },
+                    parent,
+                    state: Value::Int(0),
+                });
+                let children: Vec<NodeId> = args
+                    .iter()
+                    .map(|arg| Self::push(nodes, Some(id), arg))
+                    .collect();
+                nodes[id].kind = NodeKind::Max {
+                    children,
+                    multiset: BTreeMap::new(),
+                    null_count: 0,
+                };
+                id
+            }
+            Expression::Aggregate {
+                table,
+                x0,
+                x1,
+                y0,
+                y1,
+                kind,
+            } => {
+                nodes.push(Node {
+                    kind: NodeKind::Aggregate {
+                        table: table.clone(),
+                        x0: *x0,
+                        x1: *x1,
+                        y0: *y0,
+                        y1: *y1,
+                        accumulator: AggregateAccumulator::empty(*kind),
+                        cells: HashMap::new(),
+                    },
+                    parent,
+                    state: Value::Int(0),
+                });
+                nodes.len() - 1
             }
-            PersistentExpression::Sum { state, args } => {
-                *state = args.iter_mut().fold(0, |acc, v| {
-                    v.init(table_set);
-                    v.state() + acc
+            Expression::Binary(op, lhs, rhs) => {
+                let id = nodes.len();
+                nodes.push(Node {
+                    kind: NodeKind::Binary(*op, 0, 0),
+                    parent,
+                    state: Value::Int(0),
                 });
+                let lhs = Self::push(nodes, Some(id), lhs);
+                let rhs = Self::push(nodes, Some(id), rhs);
+                nodes[id].kind = NodeKind::Binary(*op, lhs, rhs);
+                id
+            }
+        }
+    }
+
+    pub fn state(&self) -> Value {
+        self.nodes[self.root].state
+    }
+
+    /// Apply event, return true if the root's state was modified.
+    ///
+    /// Only the `Reference` leaves subscribed to the event's cell (via
+    /// `dispatch`) and the `Aggregate` nodes whose region covers it (via
+    /// `region_dispatch`) are touched. The change is then propagated upward
+    /// through parent pointers, recomputing each ancestor's state from the
+    /// child that changed, and stops as soon as an ancestor's own state
+    /// turns out not to have moved.
+    pub fn apply(&mut self, event: &TableEvent) -> bool {
+        let TableEvent::SetValue { table, x, y, value } = event;
+
+        let root_before = self.nodes[self.root].state;
+
+        if let Some(leaves) = self.dispatch.get(&(table.clone(), *x, *y)) {
+            for leaf in leaves.clone() {
+                let old = self.nodes[leaf].state;
+                if old == *value {
+                    continue;
+                }
+                self.nodes[leaf].state = *value;
+                self.propagate_up(leaf, old, *value);
+            }
+        }
+
+        if let Some(aggregates) = self.region_dispatch.get(table) {
+            for id in aggregates.clone() {
+                if let Some((old, new)) = self.apply_aggregate(id, *x, *y, *value) {
+                    self.propagate_up(id, old, new);
+                }
+            }
+        }
+
+        self.nodes[self.root].state != root_before
+    }
+
+    /// Walk from `node` up through its ancestors, recomputing each one's
+    /// state from the child below it, stopping as soon as a parent's state
+    /// turns out not to have moved.
+    fn propagate_up(&mut self, node: NodeId, old_state: Value, new_state: Value) {
+        let mut child = node;
+        let mut old_child_state = old_state;
+        let mut new_child_state = new_state;
+        let mut current = self.nodes[node].parent;
+        while let Some(parent) = current {
+            let old_state = self.nodes[parent].state;
+            let new_state = self.recompute_parent(parent, child, old_child_state, new_child_state);
+            self.nodes[parent].state = new_state;
+            if new_state == old_state {
+                break;
+            }
+
+            child = parent;
+            old_child_state = old_state;
+            new_child_state = new_state;
+            current = self.nodes[parent].parent;
+        }
+    }
+
+    /// Apply a cell update to the `Aggregate` node `id`, ignoring it if the
+    /// cell falls outside the node's region or outside the table as it
+    /// existed at `init` time. Returns the node's (old, new) state if it
+    /// moved.
+    fn apply_aggregate(&mut self, id: NodeId, x: usize, y: usize, value: Value) -> Option<(Value, Value)> {
+        let NodeKind::Aggregate { x0, x1, y0, y1, .. } = &self.nodes[id].kind else {
+            unreachable!("region_dispatch only ever holds Aggregate nodes")
+        };
+        if x < *x0 || x >= *x1 || y < *y0 || y >= *y1 {
+            return None;
+        }
+
+        let NodeKind::Aggregate { cells, .. } = &self.nodes[id].kind else {
+            unreachable!()
+        };
+        let old = *cells.get(&(x, y))?;
+        if old == value {
+            return None;
+        }
+
+        let old_state = self.nodes[id].state;
+        let NodeKind::Aggregate {
+            accumulator, cells, ..
+        } = &mut self.nodes[id].kind
+        else {
+            unreachable!()
+        };
+        cells.insert((x, y), value);
+        accumulator.update(old, value);
+        let new_state = accumulator.state();
+        self.nodes[id].state = new_state;
+
+        (old_state != new_state).then_some((old_state, new_state))
+    }
+
+    /// Recompute `parent`'s state given that its child `changed_child` moved
+    /// from `old` to `new`.
+    ///
+    /// `Sub`/`Div`/`Neg`/`Binary` only need their operands' small `NodeId`s,
+    /// which are copied out of a shared borrow of `parent`'s kind so the
+    /// lookup of `self.nodes[lhs]`/`self.nodes[rhs]` below isn't blocked by
+    /// a held borrow. `Mul`/`Min`/`Max` instead mutate their bookkeeping
+    /// (the nonzero-product/zero-count counters, or the multiset) in place
+    /// through a mutable borrow — neither path clones the node's `children`
+    /// `Vec` or multiset, which `apply` would otherwise pay for on every
+    /// step of every upward walk.
+    fn recompute_parent(&mut self, parent: NodeId, changed_child: NodeId, old: Value, new: Value) -> Value {
+        let _ = changed_child;
+
+        enum Operands {
+            Sum,
+            Sub(NodeId, NodeId),
+            Div(NodeId, NodeId),
+            Neg(NodeId),
+            Binary(BinOp, NodeId, NodeId),
+        }
+
+        let operands = match &self.nodes[parent].kind {
+            NodeKind::Sum(_) => Operands::Sum,
+            &NodeKind::Sub(lhs, rhs) => Operands::Sub(lhs, rhs),
+            &NodeKind::Div(lhs, rhs) => Operands::Div(lhs, rhs),
+            &NodeKind::Neg(child) => Operands::Neg(child),
+            &NodeKind::Binary(op, lhs, rhs) => Operands::Binary(op, lhs, rhs),
+            NodeKind::Mul { .. } | NodeKind::Min { .. } | NodeKind::Max { .. } => {
+                return match &mut self.nodes[parent].kind {
+                    NodeKind::Mul {
+                        nonzero_product,
+                        zero_count,
+                        null_count,
+                        ..
+                    } => {
+                        if old.is_null() {
+                            *null_count -= 1;
+                        } else if old.is_zero() {
+                            *zero_count -= 1;
+                        } else {
+                            *nonzero_product = nonzero_product.div(old);
+                        }
+                        if new.is_null() {
+                            *null_count += 1;
+                        } else if new.is_zero() {
+                            *zero_count += 1;
+                        } else {
+                            *nonzero_product = nonzero_product.mul(new);
+                        }
+                        if *null_count > 0 {
+                            Value::Null
+                        } else if *zero_count > 0 {
+                            Value::Int(0)
+                        } else {
+                            *nonzero_product
+                        }
+                    }
+                    NodeKind::Min { multiset, null_count, .. } => {
+                        move_in_value_multiset(multiset, null_count, old, new);
+                        if *null_count > 0 {
+                            Value::Null
+                        } else {
+                            multiset.keys().next().map(|k| k.0).unwrap_or(Value::Int(0))
+                        }
+                    }
+                    NodeKind::Max { multiset, null_count, .. } => {
+                        move_in_value_multiset(multiset, null_count, old, new);
+                        if *null_count > 0 {
+                            Value::Null
+                        } else {
+                            multiset.keys().next_back().map(|k| k.0).unwrap_or(Value::Int(0))
+                        }
+                    }
+                    _ => unreachable!(),
+                };
             }
+            NodeKind::Number(_) | NodeKind::Reference { .. } | NodeKind::Aggregate { .. } => {
+                unreachable!("leaves are never a parent")
+            }
+        };
+
+        match operands {
+            Operands::Sum => self.nodes[parent].state.add(null_as_zero(new)).sub(null_as_zero(old)),
+            Operands::Sub(lhs, rhs) => self.nodes[lhs].state.sub(self.nodes[rhs].state),
+            Operands::Div(lhs, rhs) => self.nodes[lhs].state.div(self.nodes[rhs].state),
+            Operands::Neg(child) => self.nodes[child].state.neg(),
+            Operands::Binary(op, lhs, rhs) => op.apply(self.nodes[lhs].state, self.nodes[rhs].state),
         }
     }
+
+    /// Initialize state of persistent expression
+    pub fn init(&mut self, table_set: &TableSet) {
+        self.init_node(self.root, table_set);
+    }
+
+    fn init_node(&mut self, id: NodeId, table_set: &TableSet) -> Value {
+        let state = match self.nodes[id].kind.clone() {
+            NodeKind::Number(v) => v,
+            NodeKind::Reference { table, x, y } => table_set
+                .get(&table)
+                .and_then(|table| table.get(x, y).copied())
+                .unwrap(),
+            NodeKind::Sum(children) => children.iter().fold(Value::Int(0), |acc, &child| {
+                acc.add(null_as_zero(self.init_node(child, table_set)))
+            }),
+            NodeKind::Sub(lhs, rhs) => self.init_node(lhs, table_set).sub(self.init_node(rhs, table_set)),
+            NodeKind::Mul { children, .. } => {
+                let mut nonzero_product = Value::Int(1);
+                let mut zero_count = 0;
+                let mut null_count = 0;
+                for &child in &children {
+                    let v = self.init_node(child, table_set);
+                    if v.is_null() {
+                        null_count += 1;
+                    } else if v.is_zero() {
+                        zero_count += 1;
+                    } else {
+                        nonzero_product = nonzero_product.mul(v);
+                    }
+                }
+                self.nodes[id].kind = NodeKind::Mul {
+                    children,
+                    nonzero_product,
+                    zero_count,
+                    null_count,
+                };
+                if null_count > 0 {
+                    Value::Null
+                } else if zero_count > 0 {
+                    Value::Int(0)
+                } else {
+                    nonzero_product
+                }
+            }
+            NodeKind::Div(lhs, rhs) => self.init_node(lhs, table_set).div(self.init_node(rhs, table_set)),
+            NodeKind::Neg(child) => self.init_node(child, table_set).neg(),
+            NodeKind::Min { children, .. } => {
+                let mut multiset = BTreeMap::new();
+                let mut null_count = 0;
+                for &child in &children {
+                    let v = self.init_node(child, table_set);
+                    if v.is_null() {
+                        null_count += 1;
+                    } else {
+                        *multiset.entry(ValueKey(v)).or_insert(0) += 1;
+                    }
+                }
+                let state = if null_count > 0 {
+                    Value::Null
+                } else {
+                    multiset.keys().next().map(|k| k.0).unwrap_or(Value::Int(0))
+                };
+                self.nodes[id].kind = NodeKind::Min {
+                    children,
+                    multiset,
+                    null_count,
+                };
+                state
+            }
+            NodeKind::Max { children, .. } => {
+                let mut multiset = BTreeMap::new();
+                let mut null_count = 0;
+                for &child in &children {
+                    let v = self.init_node(child, table_set);
+                    if v.is_null() {
+                        null_count += 1;
+                    } else {
+                        *multiset.entry(ValueKey(v)).or_insert(0) += 1;
+                    }
+                }
+                let state = if null_count > 0 {
+                    Value::Null
+                } else {
+                    multiset.keys().next_back().map(|k| k.0).unwrap_or(Value::Int(0))
+                };
+                self.nodes[id].kind = NodeKind::Max {
+                    children,
+                    multiset,
+                    null_count,
+                };
+                state
+            }
+            NodeKind::Aggregate {
+                table,
+                x0,
+                x1,
+                y0,
+                y1,
+                mut accumulator,
+                ..
+            } => {
+                let mut cells = HashMap::new();
+                if let Some(t) = table_set.get(&table) {
+                    let y_end = y1.min(t.data.len());
+                    for y in y0.min(y_end)..y_end {
+                        let row = &t.data[y];
+                        let x_end = x1.min(row.len());
+                        let x_start = x0.min(x_end);
+                        for (offset, &value) in row[x_start..x_end].iter().enumerate() {
+                            accumulator.insert(value);
+                            cells.insert((x_start + offset, y), value);
+                        }
+                    }
+                }
+                let state = accumulator.state();
+                self.nodes[id].kind = NodeKind::Aggregate {
+                    table,
+                    x0,
+                    x1,
+                    y0,
+                    y1,
+                    accumulator,
+                    cells,
+                };
+                state
+            }
+            NodeKind::Binary(op, lhs, rhs) => {
+                op.apply(self.init_node(lhs, table_set), self.init_node(rhs, table_set))
+            }
+        };
+        self.nodes[id].state = state;
+        state
+    }
+
+    /// Parse a textual formula directly into an arena-backed persistent
+    /// expression; see [`Expression::parse`].
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        Ok(Self::build(&Expression::parse(input)?))
+    }
 }
 
 pub enum TableEvent {
@@ -132,21 +1172,27 @@ pub enum TableEvent {
         table: String,
         x: usize,
         y: usize,
-        value: i32,
+        value: Value,
     },
 }
 
 #[test]
 fn test() {
     let mut table_set = TableSet::new();
-    let t1 = Table::new("t1".to_string(), vec![vec![1, 2, 3]]);
-    let t2 = Table::new("t2".to_string(), vec![vec![1, 2, 3]]);
+    let t1 = Table::new(
+        "t1".to_string(),
+        vec![vec![Value::Int(1), Value::Int(2), Value::Int(3)]],
+    );
+    let t2 = Table::new(
+        "t2".to_string(),
+        vec![vec![Value::Int(1), Value::Int(2), Value::Int(3)]],
+    );
 
     table_set.insert("t1".to_string(), t1);
     table_set.insert("t2".to_string(), t2);
 
     let expr = Expression::Sum(vec![
-        Expression::Number(1),
+        Expression::Number(Value::Int(1)),
         Expression::Reference {
             table: "t1".to_string(),
             x: 1,
@@ -156,44 +1202,706 @@ fn test() {
 
     let result = expr.eval(&table_set);
 
-    assert_eq!(result, 3);
+    assert_eq!(result, Value::Int(3));
 }
 
 #[test]
 fn test_persistent() {
     let mut table_set = TableSet::new();
-    let t1 = Table::new("t1".to_string(), vec![vec![1, 2, 3]]);
-    let t2 = Table::new("t2".to_string(), vec![vec![1, 2, 3]]);
+    let t1 = Table::new(
+        "t1".to_string(),
+        vec![vec![Value::Int(1), Value::Int(2), Value::Int(3)]],
+    );
+    let t2 = Table::new(
+        "t2".to_string(),
+        vec![vec![Value::Int(1), Value::Int(2), Value::Int(3)]],
+    );
 
     table_set.insert("t1".to_string(), t1);
     table_set.insert("t2".to_string(), t2);
 
-    let mut expr = PersistentExpression::Sum {
-        state: 0,
-        args: vec![
-            PersistentExpression::Number(1),
-            PersistentExpression::Reference {
-                state: 0,
-                table: "t1".to_string(),
-                x: 1,
-                y: 0,
-            },
-        ],
-    };
+    let mut expr = PersistentExpression::build(&Expression::Sum(vec![
+        Expression::Number(Value::Int(1)),
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+    ]));
 
     expr.init(&table_set);
 
-    assert_eq!(expr.state(), 3);
+    assert_eq!(expr.state(), Value::Int(3));
 
-    table_set.get_mut("t1").unwrap().set(1, 0, 3);
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Int(3));
     let event = TableEvent::SetValue {
         table: "t1".to_string(),
         x: 1,
         y: 0,
-        value: 3,
+        value: Value::Int(3),
     };
 
     assert!(expr.apply(&event));
 
-    assert_eq!(expr.state(), 4);
+    assert_eq!(expr.state(), Value::Int(4));
+}
+
+#[test]
+fn test_dispatch_only_subscribed_formulas() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new(
+            "t1".to_string(),
+            vec![vec![Value::Int(1), Value::Int(2), Value::Int(3)]],
+        ),
+    );
+
+    // Subscribed to t1!0,0.
+    let mut subscribed = PersistentExpression::build(&Expression::Sum(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Number(Value::Int(10)),
+    ]));
+    // Not subscribed to t1!0,0, only to t1!2,0.
+    let mut unrelated = PersistentExpression::build(&Expression::Sum(vec![Expression::Reference {
+        table: "t1".to_string(),
+        x: 2,
+        y: 0,
+    }]));
+
+    subscribed.init(&table_set);
+    unrelated.init(&table_set);
+
+    assert_eq!(subscribed.state(), Value::Int(11));
+    assert_eq!(unrelated.state(), Value::Int(3));
+
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(5));
+    let event = TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(5),
+    };
+
+    assert!(subscribed.apply(&event));
+    assert!(!unrelated.apply(&event));
+
+    assert_eq!(subscribed.state(), Value::Int(15));
+    assert_eq!(unrelated.state(), Value::Int(3));
+}
+
+#[test]
+fn test_min_tracks_child_entering_and_leaving() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new(
+            "t1".to_string(),
+            vec![vec![Value::Int(5), Value::Int(2), Value::Int(9)]],
+        ),
+    );
+
+    let mut expr = PersistentExpression::build(&Expression::Min(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 2,
+            y: 0,
+        },
+    ]));
+
+    expr.init(&table_set);
+    assert_eq!(expr.state(), Value::Int(2));
+
+    // The non-extreme child (t1!0,0, currently 5) drops below the current
+    // minimum, so it becomes the new minimum.
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(1));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(1),
+    }));
+    assert_eq!(expr.state(), Value::Int(1));
+
+    // The current minimum (t1!0,0, now 1) rises, so the minimum reverts to
+    // the next smallest child.
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(7));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(7),
+    }));
+    assert_eq!(expr.state(), Value::Int(2));
+}
+
+#[test]
+fn test_mul_tracks_zero_entering_and_leaving() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new(
+            "t1".to_string(),
+            vec![vec![Value::Int(2), Value::Int(3), Value::Int(4)]],
+        ),
+    );
+
+    let mut expr = PersistentExpression::build(&Expression::Mul(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 2,
+            y: 0,
+        },
+    ]));
+
+    expr.init(&table_set);
+    assert_eq!(expr.state(), Value::Int(24));
+
+    // A zero enters the product: state collapses to 0 but the product of
+    // the remaining nonzero children is still tracked underneath.
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Int(0));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Int(0),
+    }));
+    assert_eq!(expr.state(), Value::Int(0));
+
+    // The zero leaves: the tracked nonzero product is recovered without
+    // rescanning the other children.
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Int(5));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Int(5),
+    }));
+    assert_eq!(expr.state(), Value::Int(2 * 5 * 4));
+}
+
+#[test]
+fn test_null_tracked_incrementally_for_mul_min_max() {
+    // Mul: a child going Null should collapse the product to Null (not 0,
+    // the zero-entering case already covered above), and should recover
+    // once the child stops being Null, without rescanning the others.
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new(
+            "t1".to_string(),
+            vec![vec![Value::Int(2), Value::Int(3), Value::Int(4)]],
+        ),
+    );
+
+    let mut mul = PersistentExpression::build(&Expression::Mul(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 2,
+            y: 0,
+        },
+    ]));
+    mul.init(&table_set);
+    assert_eq!(mul.state(), Value::Int(24));
+
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Null);
+    assert!(mul.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Null,
+    }));
+    assert_eq!(mul.state(), Value::Null);
+
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Int(3));
+    assert!(mul.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Int(3),
+    }));
+    assert_eq!(mul.state(), Value::Int(24));
+
+    // Min/Max: a Null child should make the extreme undefined regardless
+    // of the other children's values, and restore the multiset's tracked
+    // extreme once the Null leaves again.
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new("t1".to_string(), vec![vec![Value::Int(5), Value::Int(2)]]),
+    );
+
+    let mut min = PersistentExpression::build(&Expression::Min(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+    ]));
+    let mut max = PersistentExpression::build(&Expression::Max(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+    ]));
+    min.init(&table_set);
+    max.init(&table_set);
+    assert_eq!(min.state(), Value::Int(2));
+    assert_eq!(max.state(), Value::Int(5));
+
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Null);
+    assert!(min.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Null,
+    }));
+    assert!(max.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Null,
+    }));
+    assert_eq!(min.state(), Value::Null);
+    assert_eq!(max.state(), Value::Null);
+
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Int(2));
+    assert!(min.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Int(2),
+    }));
+    assert!(max.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Int(2),
+    }));
+    assert_eq!(min.state(), Value::Int(2));
+    assert_eq!(max.state(), Value::Int(5));
+}
+
+#[test]
+fn test_aggregate_eval() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new(
+            "t1".to_string(),
+            vec![
+                vec![Value::Int(1), Value::Int(2), Value::Int(3)],
+                vec![Value::Int(4), Value::Int(5)],
+            ],
+        ),
+    );
+
+    let region = |kind| Expression::Aggregate {
+        table: "t1".to_string(),
+        x0: 0,
+        x1: 2,
+        y0: 0,
+        y1: 2,
+        kind,
+    };
+
+    assert_eq!(region(AggregateKind::Count).eval(&table_set), Value::Int(4));
+    assert_eq!(
+        region(AggregateKind::Sum).eval(&table_set),
+        Value::Int(1 + 2 + 4 + 5)
+    );
+    assert_eq!(region(AggregateKind::Min).eval(&table_set), Value::Int(1));
+    assert_eq!(region(AggregateKind::Max).eval(&table_set), Value::Int(5));
+    assert_eq!(
+        region(AggregateKind::Avg).eval(&table_set),
+        Value::Int((1 + 2 + 4 + 5) / 4)
+    );
+}
+
+#[test]
+fn test_aggregate_ignores_events_outside_region() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new(
+            "t1".to_string(),
+            vec![
+                vec![Value::Int(1), Value::Int(2), Value::Int(3)],
+                vec![Value::Int(4), Value::Int(5), Value::Int(6)],
+            ],
+        ),
+    );
+
+    // Region covers only the first column of each row.
+    let mut expr = PersistentExpression::build(&Expression::Aggregate {
+        table: "t1".to_string(),
+        x0: 0,
+        x1: 1,
+        y0: 0,
+        y1: 2,
+        kind: AggregateKind::Sum,
+    });
+
+    expr.init(&table_set);
+    assert_eq!(expr.state(), Value::Int(1 + 4));
+
+    // Inside the region: the sum moves.
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(10));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(10),
+    }));
+    assert_eq!(expr.state(), Value::Int(10 + 4));
+
+    // Just outside the region (same row, next column): ignored.
+    table_set.get_mut("t1").unwrap().set(1, 0, Value::Int(100));
+    assert!(!expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 1,
+        y: 0,
+        value: Value::Int(100),
+    }));
+    assert_eq!(expr.state(), Value::Int(10 + 4));
+}
+
+#[test]
+fn test_snapshot_and_replay_match_live_instance() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new(
+            "t1".to_string(),
+            vec![
+                vec![Value::Int(1), Value::Int(2), Value::Int(3)],
+                vec![Value::Int(4), Value::Int(5), Value::Int(6)],
+            ],
+        ),
+    );
+
+    let mut live = PersistentExpression::build(&Expression::Sum(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 1,
+        },
+    ]));
+    live.init(&table_set);
+
+    let snapshot_path = std::env::temp_dir().join(format!(
+        "persist_expr_snapshot_{}_{}.bin",
+        std::process::id(),
+        "test_snapshot_and_replay_match_live_instance"
+    ));
+    let log_path = std::env::temp_dir().join(format!(
+        "persist_expr_log_{}_{}.bin",
+        std::process::id(),
+        "test_snapshot_and_replay_match_live_instance"
+    ));
+
+    let first_batch = [
+        TableEvent::SetValue {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+            value: Value::Int(10),
+        },
+        TableEvent::SetValue {
+            table: "t1".to_string(),
+            x: 1,
+            y: 1,
+            value: Value::Int(20),
+        },
+    ];
+    for event in &first_batch {
+        live.apply(event);
+    }
+
+    live.save_snapshot(&snapshot_path).unwrap();
+
+    let mut log = EventLog::create(&log_path).unwrap();
+    let second_batch = [
+        TableEvent::SetValue {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+            value: Value::Int(100),
+        },
+        TableEvent::SetValue {
+            table: "t1".to_string(),
+            x: 1,
+            y: 1,
+            value: Value::Int(200),
+        },
+    ];
+    for event in &second_batch {
+        live.apply(event);
+        log.append(event).unwrap();
+    }
+
+    let mut reloaded = PersistentExpression::load_snapshot(&snapshot_path).unwrap();
+    replay(&log_path, &mut reloaded).unwrap();
+
+    assert_eq!(reloaded.state(), live.state());
+    assert_eq!(reloaded.state(), Value::Int(100 + 200));
+
+    std::fs::remove_file(&snapshot_path).ok();
+    std::fs::remove_file(&log_path).ok();
+}
+
+#[test]
+fn test_sum_tolerates_null_and_adjusts_on_transition() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new("t1".to_string(), vec![vec![Value::Int(5), Value::Null]]),
+    );
+
+    let mut expr = PersistentExpression::build(&Expression::Sum(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+    ]));
+
+    // A `Null` child contributes nothing, rather than making the whole sum
+    // `Null`.
+    expr.init(&table_set);
+    assert_eq!(expr.state(), Value::Int(5));
+
+    // The non-null child goes to `Null`: the sum drops to the identity.
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Null);
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Null,
+    }));
+    assert_eq!(expr.state(), Value::Int(0));
+
+    // And back to a number: the sum picks it back up.
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(7));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(7),
+    }));
+    assert_eq!(expr.state(), Value::Int(7));
+}
+
+#[test]
+fn test_int_float_promotion() {
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new("t1".to_string(), vec![vec![Value::Int(2), Value::Float(0.5)]]),
+    );
+
+    // Int + Float promotes the whole sum to Float.
+    let expr = Expression::Sum(vec![
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 1,
+            y: 0,
+        },
+    ]);
+    assert_eq!(expr.eval(&table_set), Value::Float(2.5));
+
+    // Two Ints stay Int.
+    let both_int = Expression::Sum(vec![Expression::Number(Value::Int(2)), Expression::Number(Value::Int(3))]);
+    assert_eq!(both_int.eval(&table_set), Value::Int(5));
+}
+
+#[test]
+fn test_div_and_mod_by_zero_are_null() {
+    let table_set = TableSet::new();
+
+    assert_eq!(Expression::parse("1 / 0").unwrap().eval(&table_set), Value::Null);
+    assert_eq!(Expression::parse("1 % 0").unwrap().eval(&table_set), Value::Null);
+
+    // Reachable through the incrementally maintained arena too: a divisor
+    // that transitions to 0 at runtime shouldn't panic.
+    let mut table_set = TableSet::new();
+    table_set.insert("t1".to_string(), Table::new("t1".to_string(), vec![vec![Value::Int(2)]]));
+
+    let mut expr = PersistentExpression::build(&Expression::Div(
+        Box::new(Expression::Number(Value::Int(10))),
+        Box::new(Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        }),
+    ));
+    expr.init(&table_set);
+    assert_eq!(expr.state(), Value::Int(5));
+
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(0));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(0),
+    }));
+    assert_eq!(expr.state(), Value::Null);
+}
+
+#[test]
+fn test_int_overflow_is_null() {
+    let table_set = TableSet::new();
+
+    assert_eq!(Expression::parse("100000 * 100000").unwrap().eval(&table_set), Value::Null);
+    assert_eq!(
+        Expression::parse("0 - 2147483647 - 2").unwrap().eval(&table_set),
+        Value::Null
+    );
+    assert_eq!(Expression::parse("2 ^ 31").unwrap().eval(&table_set), Value::Null);
+    assert_eq!(
+        Expression::Number(Value::Int(i32::MIN)).eval(&table_set) / Value::Int(-1),
+        Value::Null
+    );
+    assert_eq!(
+        Expression::Number(Value::Int(i32::MIN)).eval(&table_set) % Value::Int(-1),
+        Value::Null
+    );
+
+    // Reachable through the incrementally maintained arena too: a cell
+    // edit that pushes a running product past i32::MAX shouldn't panic.
+    let mut table_set = TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        Table::new("t1".to_string(), vec![vec![Value::Int(1)]]),
+    );
+
+    let mut expr = PersistentExpression::build(&Expression::Mul(vec![
+        Expression::Number(Value::Int(i32::MAX)),
+        Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        },
+    ]));
+    expr.init(&table_set);
+    assert_eq!(expr.state(), Value::Int(i32::MAX));
+
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(2));
+    assert!(expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(2),
+    }));
+    assert_eq!(expr.state(), Value::Null);
+}
+
+#[test]
+fn test_empty_min_max_does_not_panic() {
+    let table_set = TableSet::new();
+
+    assert_eq!(Expression::Min(vec![]).eval(&table_set), Value::Int(0));
+    assert_eq!(Expression::Max(vec![]).eval(&table_set), Value::Int(0));
+    assert_eq!(Expression::parse("MIN()").unwrap().eval(&table_set), Value::Int(0));
+    assert_eq!(Expression::parse("MAX()").unwrap().eval(&table_set), Value::Int(0));
+
+    let mut min = PersistentExpression::build(&Expression::Min(vec![]));
+    min.init(&table_set);
+    assert_eq!(min.state(), Value::Int(0));
+
+    let mut max = PersistentExpression::build(&Expression::Max(vec![]));
+    max.init(&table_set);
+    assert_eq!(max.state(), Value::Int(0));
+}
+
+#[test]
+fn test_apply_reports_unchanged_when_root_state_cancels_out() {
+    let mut table_set = TableSet::new();
+    table_set.insert("t1".to_string(), Table::new("t1".to_string(), vec![vec![Value::Int(5)]]));
+
+    // Both operands reference the same cell, so any change to it cancels
+    // out at the root even though the leaf itself did change.
+    let mut expr = PersistentExpression::build(&Expression::Sub(
+        Box::new(Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        }),
+        Box::new(Expression::Reference {
+            table: "t1".to_string(),
+            x: 0,
+            y: 0,
+        }),
+    ));
+    expr.init(&table_set);
+    assert_eq!(expr.state(), Value::Int(0));
+
+    table_set.get_mut("t1").unwrap().set(0, 0, Value::Int(7));
+    assert!(!expr.apply(&TableEvent::SetValue {
+        table: "t1".to_string(),
+        x: 0,
+        y: 0,
+        value: Value::Int(7),
+    }));
+    assert_eq!(expr.state(), Value::Int(0));
 }
@@ -0,0 +1,524 @@
+//! Textual formula parser: tokenizes a formula string and climbs operator
+//! precedence levels (`or/and < comparisons < +/- < * / % < unary neg < pow`,
+//! `pow` right-associative) to build an [`Expression`].
+
+use crate::{AggregateKind, BinOp, Expression, Value};
+
+/// Error produced by [`Expression::parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The input ended while a token was still expected.
+    UnexpectedEnd,
+    /// A token was found where it doesn't belong.
+    UnexpectedToken(String),
+    /// A `(` was never closed by a matching `)`, or a `)` has no matching `(`.
+    UnbalancedParens,
+    /// A cell reference (`table!...`) was malformed.
+    InvalidReference(String),
+    /// A function call used a name that isn't a known builtin.
+    UnknownFunction(String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            ParseError::UnexpectedToken(tok) => write!(f, "unexpected token: {tok}"),
+            ParseError::UnbalancedParens => write!(f, "unbalanced parentheses"),
+            ParseError::InvalidReference(r) => write!(f, "invalid cell reference: {r}"),
+            ParseError::UnknownFunction(name) => write!(f, "unknown function: {name}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i32),
+    Ident(String),
+    Bang,
+    Comma,
+    Colon,
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Caret,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            ':' => {
+                tokens.push(Token::Colon);
+                i += 1;
+            }
+            '!' if chars.get(i + 1) == Some(&'=') => {
+                tokens.push(Token::Ne);
+                i += 2;
+            }
+            '!' => {
+                tokens.push(Token::Bang);
+                i += 1;
+            }
+            '+' => {
+                tokens.push(Token::Plus);
+                i += 1;
+            }
+            '-' => {
+                tokens.push(Token::Minus);
+                i += 1;
+            }
+            '*' => {
+                tokens.push(Token::Star);
+                i += 1;
+            }
+            '/' => {
+                tokens.push(Token::Slash);
+                i += 1;
+            }
+            '%' => {
+                tokens.push(Token::Percent);
+                i += 1;
+            }
+            '^' => {
+                tokens.push(Token::Caret);
+                i += 1;
+            }
+            '=' if chars.get(i + 1) == Some(&'=') => {
+                tokens.push(Token::Eq);
+                i += 2;
+            }
+            '<' if chars.get(i + 1) == Some(&'=') => {
+                tokens.push(Token::Le);
+                i += 2;
+            }
+            '>' if chars.get(i + 1) == Some(&'=') => {
+                tokens.push(Token::Ge);
+                i += 2;
+            }
+            '<' => {
+                tokens.push(Token::Lt);
+                i += 1;
+            }
+            '>' => {
+                tokens.push(Token::Gt);
+                i += 1;
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                tokens.push(Token::Number(text.parse().map_err(|_| {
+                    ParseError::UnexpectedToken(text.clone())
+                })?));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                tokens.push(Token::Ident(text));
+            }
+            other => return Err(ParseError::UnexpectedToken(other.to_string())),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+type Combine = fn(Box<Expression>, Box<Expression>) -> Expression;
+
+const PREC_OR: u8 = 1;
+const PREC_AND: u8 = 2;
+const PREC_CMP: u8 = 3;
+const PREC_ADD: u8 = 4;
+const PREC_MUL: u8 = 5;
+const PREC_POW: u8 = 6;
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        if tok.is_some() {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        match self.next() {
+            Some(tok) if tok == expected => Ok(()),
+            Some(tok) => Err(ParseError::UnexpectedToken(format!("{tok:?}"))),
+            None => Err(ParseError::UnexpectedEnd),
+        }
+    }
+
+    /// Parse an expression, only consuming operators whose precedence is at
+    /// least `min_prec` — the core of precedence climbing.
+    fn parse_expr(&mut self, min_prec: u8) -> Result<Expression, ParseError> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(tok) = self.peek() {
+            let (combine, prec, right_assoc): (Combine, u8, bool) =
+                match tok {
+                    Token::Plus => (|l, r| Expression::Sum(vec![*l, *r]), PREC_ADD, false),
+                    Token::Minus => (
+                        |l, r| Expression::Sub(l, r),
+                        PREC_ADD,
+                        false,
+                    ),
+                    Token::Star => (|l, r| Expression::Mul(vec![*l, *r]), PREC_MUL, false),
+                    Token::Slash => (|l, r| Expression::Div(l, r), PREC_MUL, false),
+                    Token::Percent => (
+                        |l, r| Expression::Binary(BinOp::Mod, l, r),
+                        PREC_MUL,
+                        false,
+                    ),
+                    Token::Caret => (
+                        |l, r| Expression::Binary(BinOp::Pow, l, r),
+                        PREC_POW,
+                        true,
+                    ),
+                    Token::Eq => (|l, r| Expression::Binary(BinOp::Eq, l, r), PREC_CMP, false),
+                    Token::Ne => (|l, r| Expression::Binary(BinOp::Ne, l, r), PREC_CMP, false),
+                    Token::Lt => (|l, r| Expression::Binary(BinOp::Lt, l, r), PREC_CMP, false),
+                    Token::Le => (|l, r| Expression::Binary(BinOp::Le, l, r), PREC_CMP, false),
+                    Token::Gt => (|l, r| Expression::Binary(BinOp::Gt, l, r), PREC_CMP, false),
+                    Token::Ge => (|l, r| Expression::Binary(BinOp::Ge, l, r), PREC_CMP, false),
+                    Token::Ident(name) if name.eq_ignore_ascii_case("and") => {
+                        (|l, r| Expression::Binary(BinOp::And, l, r), PREC_AND, false)
+                    }
+                    Token::Ident(name) if name.eq_ignore_ascii_case("or") => {
+                        (|l, r| Expression::Binary(BinOp::Or, l, r), PREC_OR, false)
+                    }
+                    _ => break,
+                };
+
+            if prec < min_prec {
+                break;
+            }
+            self.next();
+
+            let next_min_prec = if right_assoc { prec } else { prec + 1 };
+            let rhs = self.parse_expr(next_min_prec)?;
+            lhs = combine(Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    /// Unary `-` binds looser than `^` (`-2^2` is `-(2^2)`), so its operand
+    /// is parsed at `PREC_POW`.
+    fn parse_unary(&mut self) -> Result<Expression, ParseError> {
+        if let Some(Token::Minus) = self.peek() {
+            self.next();
+            let operand = self.parse_expr(PREC_POW)?;
+            return Ok(Expression::Neg(Box::new(operand)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, ParseError> {
+        match self.next() {
+            Some(Token::Number(v)) => Ok(Expression::Number(Value::Int(v))),
+            Some(Token::LParen) => {
+                let expr = self.parse_expr(0)?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(ParseError::UnbalancedParens),
+                }
+            }
+            Some(Token::Ident(name)) => match self.peek() {
+                Some(Token::Bang) => {
+                    self.next();
+                    self.parse_cell_reference(name)
+                }
+                Some(Token::LParen) => {
+                    self.next();
+                    self.parse_call(name)
+                }
+                _ => Err(ParseError::UnexpectedToken(name)),
+            },
+            Some(tok) => Err(ParseError::UnexpectedToken(format!("{tok:?}"))),
+            None => Err(ParseError::UnexpectedEnd),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<usize, ParseError> {
+        match self.next() {
+            Some(Token::Number(v)) if v >= 0 => Ok(v as usize),
+            Some(tok) => Err(ParseError::UnexpectedToken(format!("{tok:?}"))),
+            None => Err(ParseError::UnexpectedEnd),
+        }
+    }
+
+    /// A bare cell reference: `table!x,y` (numeric) or `table!A1` (A1-style).
+    ///
+    /// `table` is accepted as-is: the parser has no `TableSet` to check it
+    /// against, so an unknown table name is never a parse error — it
+    /// surfaces later, as a panic on the missing entry, when
+    /// [`Expression::eval`] looks it up.
+    fn parse_cell_reference(&mut self, table: String) -> Result<Expression, ParseError> {
+        if let Some(Token::Ident(a1)) = self.peek() {
+            let (x, y) = parse_a1(a1)?;
+            self.next();
+            return Ok(Expression::Reference { table, x, y });
+        }
+
+        let x = self.parse_number()?;
+        self.expect(Token::Comma)?;
+        let y = self.parse_number()?;
+        Ok(Expression::Reference { table, x, y })
+    }
+
+    /// A rectangular range reference used as the sole argument to a
+    /// `RANGE_*` function: `table!x0,y0:x1,y1`.
+    fn parse_range_reference(&mut self) -> Result<(String, usize, usize, usize, usize), ParseError> {
+        let table = match self.next() {
+            Some(Token::Ident(name)) => name,
+            Some(tok) => return Err(ParseError::InvalidReference(format!("{tok:?}"))),
+            None => return Err(ParseError::UnexpectedEnd),
+        };
+        self.expect(Token::Bang)?;
+        let x0 = self.parse_number()?;
+        self.expect(Token::Comma)?;
+        let y0 = self.parse_number()?;
+        self.expect(Token::Colon)?;
+        let x1 = self.parse_number()?;
+        self.expect(Token::Comma)?;
+        let y1 = self.parse_number()?;
+        Ok((table, x0, y0, x1, y1))
+    }
+
+    fn parse_call(&mut self, name: String) -> Result<Expression, ParseError> {
+        let upper = name.to_ascii_uppercase();
+        let expr = match upper.as_str() {
+            "SUM" | "MIN" | "MAX" => {
+                let args = self.parse_arg_list()?;
+                match upper.as_str() {
+                    "SUM" => Expression::Sum(args),
+                    "MIN" => Expression::Min(args),
+                    "MAX" => Expression::Max(args),
+                    _ => unreachable!(),
+                }
+            }
+            "RANGE_SUM" | "RANGE_MIN" | "RANGE_MAX" | "RANGE_COUNT" | "RANGE_AVG" => {
+                let (table, x0, y0, x1, y1) = self.parse_range_reference()?;
+                let kind = match upper.as_str() {
+                    "RANGE_SUM" => AggregateKind::Sum,
+                    "RANGE_MIN" => AggregateKind::Min,
+                    "RANGE_MAX" => AggregateKind::Max,
+                    "RANGE_COUNT" => AggregateKind::Count,
+                    "RANGE_AVG" => AggregateKind::Avg,
+                    _ => unreachable!(),
+                };
+                Expression::Aggregate {
+                    table,
+                    x0,
+                    x1,
+                    y0,
+                    y1,
+                    kind,
+                }
+            }
+            _ => return Err(ParseError::UnknownFunction(name)),
+        };
+        match self.next() {
+            Some(Token::RParen) => Ok(expr),
+            _ => Err(ParseError::UnbalancedParens),
+        }
+    }
+
+    fn parse_arg_list(&mut self) -> Result<Vec<Expression>, ParseError> {
+        let mut args = Vec::new();
+        if let Some(Token::RParen) = self.peek() {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_expr(0)?);
+            match self.peek() {
+                Some(Token::Comma) => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Parse an A1-style cell name (e.g. `B12`) into 0-indexed `(x, y)`.
+fn parse_a1(s: &str) -> Result<(usize, usize), ParseError> {
+    let letters_end = s.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (letters, digits) = s.split_at(letters_end);
+    if letters.is_empty() || digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(ParseError::InvalidReference(s.to_string()));
+    }
+
+    let mut col = 0usize;
+    for c in letters.chars() {
+        col = col * 26 + (c.to_ascii_uppercase() as usize - 'A' as usize + 1);
+    }
+    let row: usize = digits
+        .parse()
+        .map_err(|_| ParseError::InvalidReference(s.to_string()))?;
+    if row == 0 {
+        return Err(ParseError::InvalidReference(s.to_string()));
+    }
+
+    Ok((col - 1, row - 1))
+}
+
+pub fn parse(input: &str) -> Result<Expression, ParseError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.parse_expr(0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(Token::RParen) => Err(ParseError::UnbalancedParens),
+        Some(tok) => Err(ParseError::UnexpectedToken(format!("{tok:?}"))),
+    }
+}
+
+#[test]
+fn test_precedence() {
+    let table_set = crate::TableSet::new();
+    assert_eq!(
+        Expression::parse("1 + 2 * 3").unwrap().eval(&table_set),
+        Value::Int(7)
+    );
+    assert_eq!(
+        Expression::parse("(1 + 2) * 3").unwrap().eval(&table_set),
+        Value::Int(9)
+    );
+    assert_eq!(
+        Expression::parse("2 + 3 * 4 - 1").unwrap().eval(&table_set),
+        Value::Int(13)
+    );
+}
+
+#[test]
+fn test_associativity() {
+    let table_set = crate::TableSet::new();
+    // Left-associative: (10 - 3) - 2 = 5, not 10 - (3 - 2) = 9.
+    assert_eq!(
+        Expression::parse("10 - 3 - 2").unwrap().eval(&table_set),
+        Value::Int(5)
+    );
+    // Right-associative: 2 ^ (3 ^ 2) = 512, not (2 ^ 3) ^ 2 = 64.
+    assert_eq!(
+        Expression::parse("2 ^ 3 ^ 2").unwrap().eval(&table_set),
+        Value::Int(512)
+    );
+    // Unary neg binds looser than pow: -(2 ^ 2) = -4.
+    assert_eq!(
+        Expression::parse("-2 ^ 2").unwrap().eval(&table_set),
+        Value::Int(-4)
+    );
+}
+
+#[test]
+fn test_cell_references_and_functions() {
+    let mut table_set = crate::TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        crate::Table::new(
+            "t1".to_string(),
+            vec![vec![Value::Int(1), Value::Int(2), Value::Int(3)]],
+        ),
+    );
+    table_set.insert(
+        "t2".to_string(),
+        crate::Table::new("t2".to_string(), vec![vec![Value::Int(10)]]),
+    );
+
+    // `t1!1,0` (numeric) and `t1!B1` (A1-style) both reach the same cell.
+    assert_eq!(Expression::parse("t1!1,0").unwrap().eval(&table_set), Value::Int(2));
+    assert_eq!(Expression::parse("t1!B1").unwrap().eval(&table_set), Value::Int(2));
+
+    assert_eq!(
+        Expression::parse("SUM(t1!0,0, 1) * (3 - t2!0,0)")
+            .unwrap()
+            .eval(&table_set),
+        Value::Int((1 + 1) * (3 - 10))
+    );
+}
+
+#[test]
+fn test_nested_function_calls() {
+    let mut table_set = crate::TableSet::new();
+    table_set.insert(
+        "t1".to_string(),
+        crate::Table::new(
+            "t1".to_string(),
+            vec![vec![Value::Int(1), Value::Int(2), Value::Int(3)]],
+        ),
+    );
+
+    let expr = Expression::parse("MAX(SUM(t1!0,0, t1!1,0), MIN(1, 2), RANGE_SUM(t1!0,0:3,1))").unwrap();
+    assert_eq!(expr.eval(&table_set), Value::Int(1 + 2 + 3));
+}
+
+#[test]
+fn test_parse_error_unbalanced_parens() {
+    assert_eq!(
+        Expression::parse("(1 + 2").unwrap_err(),
+        ParseError::UnbalancedParens
+    );
+    assert_eq!(
+        Expression::parse("1 + 2)").unwrap_err(),
+        ParseError::UnbalancedParens
+    );
+    assert_eq!(Expression::parse("1 +").unwrap_err(), ParseError::UnexpectedEnd);
+}
+
+// There's no `test_parse_error_unknown_table`: table names aren't resolved
+// until `Expression::eval` runs against a `TableSet` (see the note on
+// `parse_cell_reference`), so an unknown table can't be a `ParseError`.
+// `test_parse_error_unknown_function` is the closest parse-time equivalent,
+// since builtins *are* validated by name at parse time.
+#[test]
+fn test_parse_error_unknown_function() {
+    assert_eq!(
+        Expression::parse("NOPE(1, 2)").unwrap_err(),
+        ParseError::UnknownFunction("NOPE".to_string())
+    );
+}
@@ -0,0 +1,502 @@
+//! Durable storage for a [`PersistentExpression`]: a snapshot of the full
+//! arena plus an append-only log of the [`TableEvent`]s applied since the
+//! snapshot was taken, so a crashed process can recover by reloading the
+//! snapshot and replaying only the events logged after it.
+//!
+//! The encoding is a hand-rolled binary format (tag byte + fixed-width
+//! little-endian fields) rather than a `serde` derive, since nothing else in
+//! this crate depends on `serde`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::PersistentExpression;
+use crate::{AggregateAccumulator, BinOp, Node, NodeId, NodeKind, TableEvent, Value, ValueKey};
+
+fn write_u64(w: &mut impl Write, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_usize(w: &mut impl Write, v: usize) -> io::Result<()> {
+    write_u64(w, v as u64)
+}
+
+fn read_usize(r: &mut impl Read) -> io::Result<usize> {
+    Ok(read_u64(r)? as usize)
+}
+
+fn write_i32(w: &mut impl Write, v: i32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn write_f64(w: &mut impl Write, v: f64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+
+fn write_u8(w: &mut impl Write, v: u8) -> io::Result<()> {
+    w.write_all(&[v])
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn write_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write_u64(w, s.len() as u64)?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let len = read_u64(r)? as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_value(w: &mut impl Write, value: Value) -> io::Result<()> {
+    match value {
+        Value::Int(v) => {
+            write_u8(w, 0)?;
+            write_i32(w, v)
+        }
+        Value::Float(v) => {
+            write_u8(w, 1)?;
+            write_f64(w, v)
+        }
+        Value::Bool(v) => {
+            write_u8(w, 2)?;
+            write_u8(w, v as u8)
+        }
+        Value::Null => write_u8(w, 3),
+    }
+}
+
+fn read_value(r: &mut impl Read) -> io::Result<Value> {
+    Ok(match read_u8(r)? {
+        0 => Value::Int(read_i32(r)?),
+        1 => Value::Float(read_f64(r)?),
+        2 => Value::Bool(read_u8(r)? != 0),
+        3 => Value::Null,
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad Value tag {tag}"))),
+    })
+}
+
+fn write_node_ids(w: &mut impl Write, ids: &[NodeId]) -> io::Result<()> {
+    write_usize(w, ids.len())?;
+    for &id in ids {
+        write_usize(w, id)?;
+    }
+    Ok(())
+}
+
+fn read_node_ids(r: &mut impl Read) -> io::Result<Vec<NodeId>> {
+    let len = read_usize(r)?;
+    (0..len).map(|_| read_usize(r)).collect()
+}
+
+fn write_multiset(w: &mut impl Write, multiset: &std::collections::BTreeMap<ValueKey, usize>) -> io::Result<()> {
+    write_usize(w, multiset.len())?;
+    for (key, &count) in multiset {
+        write_value(w, key.0)?;
+        write_usize(w, count)?;
+    }
+    Ok(())
+}
+
+fn read_multiset(r: &mut impl Read) -> io::Result<std::collections::BTreeMap<ValueKey, usize>> {
+    let len = read_usize(r)?;
+    let mut multiset = std::collections::BTreeMap::new();
+    for _ in 0..len {
+        let value = read_value(r)?;
+        let count = read_usize(r)?;
+        multiset.insert(ValueKey(value), count);
+    }
+    Ok(multiset)
+}
+
+fn write_cells(w: &mut impl Write, cells: &std::collections::HashMap<(usize, usize), Value>) -> io::Result<()> {
+    write_usize(w, cells.len())?;
+    for (&(x, y), &value) in cells {
+        write_usize(w, x)?;
+        write_usize(w, y)?;
+        write_value(w, value)?;
+    }
+    Ok(())
+}
+
+fn read_cells(r: &mut impl Read) -> io::Result<std::collections::HashMap<(usize, usize), Value>> {
+    let len = read_usize(r)?;
+    let mut cells = std::collections::HashMap::new();
+    for _ in 0..len {
+        let x = read_usize(r)?;
+        let y = read_usize(r)?;
+        let value = read_value(r)?;
+        cells.insert((x, y), value);
+    }
+    Ok(cells)
+}
+
+fn write_bin_op(w: &mut impl Write, op: BinOp) -> io::Result<()> {
+    let tag = match op {
+        BinOp::Eq => 0,
+        BinOp::Ne => 1,
+        BinOp::Lt => 2,
+        BinOp::Le => 3,
+        BinOp::Gt => 4,
+        BinOp::Ge => 5,
+        BinOp::And => 6,
+        BinOp::Or => 7,
+        BinOp::Mod => 8,
+        BinOp::Pow => 9,
+    };
+    write_u8(w, tag)
+}
+
+fn read_bin_op(r: &mut impl Read) -> io::Result<BinOp> {
+    Ok(match read_u8(r)? {
+        0 => BinOp::Eq,
+        1 => BinOp::Ne,
+        2 => BinOp::Lt,
+        3 => BinOp::Le,
+        4 => BinOp::Gt,
+        5 => BinOp::Ge,
+        6 => BinOp::And,
+        7 => BinOp::Or,
+        8 => BinOp::Mod,
+        9 => BinOp::Pow,
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad BinOp tag {tag}"))),
+    })
+}
+
+fn write_accumulator(w: &mut impl Write, accumulator: &AggregateAccumulator) -> io::Result<()> {
+    match accumulator {
+        AggregateAccumulator::Count(count) => {
+            write_u8(w, 0)?;
+            write_usize(w, *count)
+        }
+        AggregateAccumulator::Sum(sum) => {
+            write_u8(w, 1)?;
+            write_value(w, *sum)
+        }
+        AggregateAccumulator::Avg { sum, count } => {
+            write_u8(w, 2)?;
+            write_value(w, *sum)?;
+            write_usize(w, *count)
+        }
+        AggregateAccumulator::Min(multiset) => {
+            write_u8(w, 3)?;
+            write_multiset(w, multiset)
+        }
+        AggregateAccumulator::Max(multiset) => {
+            write_u8(w, 4)?;
+            write_multiset(w, multiset)
+        }
+    }
+}
+
+fn read_accumulator(r: &mut impl Read) -> io::Result<AggregateAccumulator> {
+    Ok(match read_u8(r)? {
+        0 => AggregateAccumulator::Count(read_usize(r)?),
+        1 => AggregateAccumulator::Sum(read_value(r)?),
+        2 => {
+            let sum = read_value(r)?;
+            let count = read_usize(r)?;
+            AggregateAccumulator::Avg { sum, count }
+        }
+        3 => AggregateAccumulator::Min(read_multiset(r)?),
+        4 => AggregateAccumulator::Max(read_multiset(r)?),
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad AggregateAccumulator tag {tag}"))),
+    })
+}
+
+fn write_node_kind(w: &mut impl Write, kind: &NodeKind) -> io::Result<()> {
+    match kind {
+        NodeKind::Number(v) => {
+            write_u8(w, 0)?;
+            write_value(w, *v)
+        }
+        NodeKind::Reference { table, x, y } => {
+            write_u8(w, 1)?;
+            write_string(w, table)?;
+            write_usize(w, *x)?;
+            write_usize(w, *y)
+        }
+        NodeKind::Sum(children) => {
+            write_u8(w, 2)?;
+            write_node_ids(w, children)
+        }
+        NodeKind::Sub(lhs, rhs) => {
+            write_u8(w, 3)?;
+            write_usize(w, *lhs)?;
+            write_usize(w, *rhs)
+        }
+        NodeKind::Mul {
+            children,
+            nonzero_product,
+            zero_count,
+            null_count,
+        } => {
+            write_u8(w, 4)?;
+            write_node_ids(w, children)?;
+            write_value(w, *nonzero_product)?;
+            write_usize(w, *zero_count)?;
+            write_usize(w, *null_count)
+        }
+        NodeKind::Div(lhs, rhs) => {
+            write_u8(w, 5)?;
+            write_usize(w, *lhs)?;
+            write_usize(w, *rhs)
+        }
+        NodeKind::Neg(arg) => {
+            write_u8(w, 6)?;
+            write_usize(w, *arg)
+        }
+        NodeKind::Min {
+            children,
+            multiset,
+            null_count,
+        } => {
+            write_u8(w, 7)?;
+            write_node_ids(w, children)?;
+            write_multiset(w, multiset)?;
+            write_usize(w, *null_count)
+        }
+        NodeKind::Max {
+            children,
+            multiset,
+            null_count,
+        } => {
+            write_u8(w, 8)?;
+            write_node_ids(w, children)?;
+            write_multiset(w, multiset)?;
+            write_usize(w, *null_count)
+        }
+        NodeKind::Aggregate {
+            table,
+            x0,
+            x1,
+            y0,
+            y1,
+            accumulator,
+            cells,
+        } => {
+            write_u8(w, 9)?;
+            write_string(w, table)?;
+            write_usize(w, *x0)?;
+            write_usize(w, *x1)?;
+            write_usize(w, *y0)?;
+            write_usize(w, *y1)?;
+            write_accumulator(w, accumulator)?;
+            write_cells(w, cells)
+        }
+        NodeKind::Binary(op, lhs, rhs) => {
+            write_u8(w, 10)?;
+            write_bin_op(w, *op)?;
+            write_usize(w, *lhs)?;
+            write_usize(w, *rhs)
+        }
+    }
+}
+
+fn read_node_kind(r: &mut impl Read) -> io::Result<NodeKind> {
+    Ok(match read_u8(r)? {
+        0 => NodeKind::Number(read_value(r)?),
+        1 => NodeKind::Reference {
+            table: read_string(r)?,
+            x: read_usize(r)?,
+            y: read_usize(r)?,
+        },
+        2 => NodeKind::Sum(read_node_ids(r)?),
+        3 => NodeKind::Sub(read_usize(r)?, read_usize(r)?),
+        4 => {
+            let children = read_node_ids(r)?;
+            let nonzero_product = read_value(r)?;
+            let zero_count = read_usize(r)?;
+            let null_count = read_usize(r)?;
+            NodeKind::Mul {
+                children,
+                nonzero_product,
+                zero_count,
+                null_count,
+            }
+        }
+        5 => NodeKind::Div(read_usize(r)?, read_usize(r)?),
+        6 => NodeKind::Neg(read_usize(r)?),
+        7 => NodeKind::Min {
+            children: read_node_ids(r)?,
+            multiset: read_multiset(r)?,
+            null_count: read_usize(r)?,
+        },
+        8 => NodeKind::Max {
+            children: read_node_ids(r)?,
+            multiset: read_multiset(r)?,
+            null_count: read_usize(r)?,
+        },
+        9 => {
+            let table = read_string(r)?;
+            let x0 = read_usize(r)?;
+            let x1 = read_usize(r)?;
+            let y0 = read_usize(r)?;
+            let y1 = read_usize(r)?;
+            let accumulator = read_accumulator(r)?;
+            let cells = read_cells(r)?;
+            NodeKind::Aggregate {
+                table,
+                x0,
+                x1,
+                y0,
+                y1,
+                accumulator,
+                cells,
+            }
+        }
+        10 => {
+            let op = read_bin_op(r)?;
+            let lhs = read_usize(r)?;
+            let rhs = read_usize(r)?;
+            NodeKind::Binary(op, lhs, rhs)
+        }
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad NodeKind tag {tag}"))),
+    })
+}
+
+fn write_node(w: &mut impl Write, node: &Node) -> io::Result<()> {
+    write_node_kind(w, &node.kind)?;
+    match node.parent {
+        Some(parent) => {
+            write_u8(w, 1)?;
+            write_usize(w, parent)?;
+        }
+        None => write_u8(w, 0)?,
+    }
+    write_value(w, node.state)
+}
+
+fn read_node(r: &mut impl Read) -> io::Result<Node> {
+    let kind = read_node_kind(r)?;
+    let parent = match read_u8(r)? {
+        0 => None,
+        1 => Some(read_usize(r)?),
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad parent tag {tag}"))),
+    };
+    let state = read_value(r)?;
+    Ok(Node { kind, parent, state })
+}
+
+fn write_table_event(w: &mut impl Write, event: &TableEvent) -> io::Result<()> {
+    let TableEvent::SetValue { table, x, y, value } = event;
+    write_string(w, table)?;
+    write_usize(w, *x)?;
+    write_usize(w, *y)?;
+    write_value(w, *value)
+}
+
+fn read_table_event(r: &mut impl Read) -> io::Result<TableEvent> {
+    let table = read_string(r)?;
+    let x = read_usize(r)?;
+    let y = read_usize(r)?;
+    let value = read_value(r)?;
+    Ok(TableEvent::SetValue { table, x, y, value })
+}
+
+impl PersistentExpression {
+    /// Serialize the full arena (including each node's incrementally
+    /// maintained state) to `path`. `dispatch`/`region_dispatch` are not
+    /// written since [`Self::load_snapshot`] rebuilds them from `nodes`.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        write_usize(&mut file, self.root)?;
+        write_usize(&mut file, self.nodes.len())?;
+        for node in &self.nodes {
+            write_node(&mut file, node)?;
+        }
+        file.flush()
+    }
+
+    /// Load a snapshot written by [`Self::save_snapshot`].
+    pub fn load_snapshot(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let root = read_usize(&mut file)?;
+        let count = read_usize(&mut file)?;
+        let mut nodes = Vec::with_capacity(count);
+        for _ in 0..count {
+            nodes.push(read_node(&mut file)?);
+        }
+        let (dispatch, region_dispatch) = Self::build_dispatch(&nodes);
+        Ok(Self {
+            nodes,
+            root,
+            dispatch,
+            region_dispatch,
+        })
+    }
+}
+
+/// An append-only log of [`TableEvent`]s, so a reader can replay everything
+/// applied since the last snapshot.
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    /// Create a fresh, empty log at `path`, truncating it if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Open an existing log (or create one) for appending further events.
+    pub fn open_append(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Append `event` to the log. `File` does no buffering of its own, so
+    /// the event is handed to the OS before this returns; it is not
+    /// `fsync`ed.
+    pub fn append(&mut self, event: &TableEvent) -> io::Result<()> {
+        write_table_event(&mut self.file, event)?;
+        self.file.flush()
+    }
+}
+
+/// Replay every event logged at `path` into `expr`, in the order they were
+/// appended, by calling [`PersistentExpression::apply`] for each.
+pub fn replay(path: impl AsRef<Path>, expr: &mut PersistentExpression) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    loop {
+        match read_table_event(&mut file) {
+            Ok(event) => {
+                expr.apply(&event);
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}